@@ -31,7 +31,9 @@ use binder::{ParcelFileDescriptor, Strong};
 use compos_aidl_interface::aidl::com::android::compos::ICompOsService::ICompOsService;
 use log::{info, warn};
 use rustutils::system_properties;
-use std::fs::{self, File};
+use std::collections::hash_map::DefaultHasher;
+use std::fs::{self, File, OpenOptions};
+use std::hash::{Hash, Hasher};
 use std::num::NonZeroU32;
 use std::path::{Path, PathBuf};
 use vmclient::{DeathReason, ErrorCode, VmInstance, VmWaitError};
@@ -52,6 +54,22 @@ pub struct VmParameters {
     pub memory_mib: Option<i32>,
     /// Whether the VM prefers staged APEXes or activated ones (false; default)
     pub prefer_staged: bool,
+    /// If present, gives the VM an encrypted storage image backed by the given file, for
+    /// persisting confidential state across runs.
+    pub encrypted_storage: Option<EncryptedStorage>,
+    /// Additional APKs (beyond the build-manifest APKs) whose fs-verity idsigs should be
+    /// generated and mounted into the VM, e.g. extra trusted manifests or plugin code.
+    pub extra_apks: Vec<PathBuf>,
+}
+
+/// Describes the encrypted storage image to give to a VM.
+#[derive(Debug, Clone)]
+pub struct EncryptedStorage {
+    /// Path of the backing file for the encrypted storage image.
+    pub path: PathBuf,
+    /// Size of the encrypted storage image, in bytes. Only used when the backing file doesn't
+    /// already exist, to format it on first use.
+    pub size_bytes: u64,
 }
 
 impl ComposClient {
@@ -84,7 +102,7 @@ impl ComposClient {
         // Prepare a few things based on whether /system_ext exists, including:
         // 1. generate the additional idsig FD for the APK from /system_ext, then pass to VS
         // 2. select the correct VM config json
-        let (extra_idsigs, has_system_ext) =
+        let (mut extra_idsigs, has_system_ext) =
             if let Ok(manifest_ext_apk_fd) = File::open(BUILD_MANIFEST_SYSTEM_EXT_APK_PATH) {
                 // Optional idsig in /system_ext is found, so prepare additionally.
                 let manifest_ext_apk_fd = ParcelFileDescriptor::new(manifest_ext_apk_fd);
@@ -95,8 +113,17 @@ impl ComposClient {
             } else {
                 (vec![idsig_manifest_apk_fd], false)
             };
+        for apk_path in &parameters.extra_apks {
+            extra_idsigs.push(prepare_extra_apk_idsig(service, data_dir, apk_path)?);
+        }
         let config_path = get_vm_config_path(has_system_ext, parameters.prefer_staged);
 
+        let encrypted_storage_fd = parameters
+            .encrypted_storage
+            .as_ref()
+            .map(prepare_encrypted_storage)
+            .transpose()?;
+
         let debug_level = if parameters.debug_mode { DebugLevel::FULL } else { DebugLevel::NONE };
 
         let (console_fd, log_fd) = if debug_level == DebugLevel::NONE {
@@ -116,7 +143,7 @@ impl ComposClient {
             apk: Some(apk_fd),
             idsig: Some(idsig_fd),
             instanceImage: Some(instance_fd),
-            encryptedStorageImage: None,
+            encryptedStorageImage: encrypted_storage_fd,
             payload: Payload::ConfigPath(config_path),
             debugLevel: debug_level,
             extraIdsigs: extra_idsigs,
@@ -209,6 +236,56 @@ fn prepare_idsig(
     Ok(idsig_fd)
 }
 
+/// Opens the backing file for an encrypted storage image, creating and sizing it first if it
+/// doesn't already exist (in which case the VM will format it on first use), or reusing it as-is
+/// otherwise.
+fn prepare_encrypted_storage(storage: &EncryptedStorage) -> Result<ParcelFileDescriptor> {
+    let file = if storage.path.exists() {
+        OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(&storage.path)
+            .context("Failed to open encrypted storage image")?
+    } else {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create_new(true)
+            .open(&storage.path)
+            .context("Failed to create encrypted storage image")?;
+        file.set_len(storage.size_bytes).context("Failed to size encrypted storage image")?;
+        file
+    };
+    Ok(ParcelFileDescriptor::new(file))
+}
+
+/// Opens an additional trusted APK supplied via `VmParameters::extra_apks`, and prepares its
+/// fs-verity idsig, the same way the build-manifest APKs are handled.
+///
+/// `extra_apks` entries may point into a read-only partition (e.g. `/apex`, `/system`), so unlike
+/// `idsig`/`idsig_manifest_apk`/`idsig_manifest_ext_apk` the idsig can't be cached alongside the
+/// APK itself; it's cached under `data_dir` instead, keyed off the APK's full source path (not
+/// just its file name, since two different `extra_apks` entries can share a basename) so unrelated
+/// APKs with the same name never collide on the same cached idsig.
+fn prepare_extra_apk_idsig(
+    service: &dyn IVirtualizationService,
+    data_dir: &Path,
+    apk_path: &Path,
+) -> Result<ParcelFileDescriptor> {
+    let apk_fd = File::open(apk_path)
+        .with_context(|| format!("Failed to open extra APK {:?}", apk_path))?;
+    let apk_fd = ParcelFileDescriptor::new(apk_fd);
+    let idsig_file_name = apk_path
+        .file_name()
+        .with_context(|| format!("Invalid extra APK path {:?}", apk_path))?;
+    let mut hasher = DefaultHasher::new();
+    apk_path.hash(&mut hasher);
+    let idsig_file_name =
+        format!("{:016x}-{}", hasher.finish(), idsig_file_name.to_string_lossy());
+    let idsig_path = data_dir.join(idsig_file_name).with_extension("idsig");
+    prepare_idsig(service, &apk_fd, &idsig_path)
+}
+
 fn want_protected_vm() -> Result<bool> {
     let have_protected_vm =
         system_properties::read_bool("ro.boot.hypervisor.protected_vm.supported", false)?;
@@ -17,51 +17,96 @@
 use anyhow::{bail, Result};
 use libc::getxattr;
 use std::ffi::CString;
+use std::fs::File;
 use std::io;
-use std::os::unix::io::RawFd;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::path::Path;
 
 /// Magic used in fs-verity digest
 const FS_VERITY_MAGIC: &[u8; 8] = b"FSVerity";
 
-/// Hash algorithm to use from linux/fsverity.h
+/// Hash algorithm IDs, from linux/fsverity.h
 const FS_VERITY_HASH_ALG_SHA256: u8 = 1;
+const FS_VERITY_HASH_ALG_SHA512: u8 = 2;
 
 const SHA256_HASH_SIZE: usize = 32;
+const SHA512_HASH_SIZE: usize = 64;
 
-/// Size of `struct fsverity_formatted_digest` with SHA-256 in bytes.
-const FORMATTED_SHA256_DIGEST_SIZE: usize = 12 + SHA256_HASH_SIZE;
+/// Size in bytes of the fixed part of `struct fsverity_formatted_digest` in Linux, i.e. everything
+/// but the variable-length digest itself.
+const FORMATTED_DIGEST_PREFIX_SIZE: usize = 12;
 
-/// Bytes of `struct fsverity_formatted_digest` in Linux with SHA-256.
-pub type FormattedSha256Digest = [u8; FORMATTED_SHA256_DIGEST_SIZE];
-
-/// Bytes of SHA256 digest
+/// Bytes of a SHA-256 digest.
 pub type Sha256Digest = [u8; SHA256_HASH_SIZE];
 
-/// Returns the fs-verity measurement/digest. Currently only SHA256 is supported.
-pub fn measure(fd: RawFd) -> Result<Sha256Digest> {
+/// Bytes of a SHA-512 digest.
+pub type Sha512Digest = [u8; SHA512_HASH_SIZE];
+
+/// An fs-verity measurement/digest, tagged with the hash algorithm it was computed with.
+///
+/// This replaces the previous fixed-size `Sha256Digest`/`FormattedSha256Digest` return types of
+/// [`measure`]/[`to_formatted_digest`]. There are no other callers of those functions in this
+/// tree, so the signature change doesn't break any build here, but any out-of-tree caller that
+/// pattern-matches a 32-byte array will need to switch to matching on this enum.
+#[derive(Debug, PartialEq, Eq)]
+pub enum FsVerityDigest {
+    Sha256(Sha256Digest),
+    Sha512(Sha512Digest),
+}
+
+impl FsVerityDigest {
+    fn alg_id(&self) -> u8 {
+        match self {
+            Self::Sha256(_) => FS_VERITY_HASH_ALG_SHA256,
+            Self::Sha512(_) => FS_VERITY_HASH_ALG_SHA512,
+        }
+    }
+
+    fn bytes(&self) -> &[u8] {
+        match self {
+            Self::Sha256(digest) => digest,
+            Self::Sha512(digest) => digest,
+        }
+    }
+}
+
+/// Returns the fs-verity measurement/digest of the file at `path`.
+pub fn measure_path<P: AsRef<Path>>(path: P) -> Result<FsVerityDigest> {
+    let file = File::open(path)?;
+    measure(file.as_raw_fd())
+}
+
+/// Returns the fs-verity measurement/digest of the open file `fd`.
+pub fn measure(fd: RawFd) -> Result<FsVerityDigest> {
     // TODO(b/196635431): Unfortunately, the FUSE API doesn't allow authfs to implement the standard
     // fs-verity ioctls. Until the kernel allows, use the alternative xattr that authfs provides.
     let path = CString::new(format!("/proc/self/fd/{}", fd).as_str()).unwrap();
     let name = CString::new("authfs.fsverity.digest").unwrap();
-    let mut buf = [0u8; SHA256_HASH_SIZE];
+
+    // authfs doesn't tell us up front which algorithm a given file was signed with, so size the
+    // read buffer for the largest digest we support and match the returned size against each
+    // algorithm in turn.
+    let mut buf = [0u8; SHA512_HASH_SIZE];
     // SAFETY: getxattr should not write beyond the given buffer size.
     let size = unsafe {
         getxattr(path.as_ptr(), name.as_ptr(), buf.as_mut_ptr() as *mut libc::c_void, buf.len())
     };
     if size < 0 {
         bail!("Failed to getxattr: {}", io::Error::last_os_error());
-    } else if size != SHA256_HASH_SIZE as isize {
-        bail!("Unexpected hash size: {}", size);
-    } else {
-        Ok(buf)
+    }
+    match size as usize {
+        SHA256_HASH_SIZE => Ok(FsVerityDigest::Sha256(buf[..SHA256_HASH_SIZE].try_into().unwrap())),
+        SHA512_HASH_SIZE => Ok(FsVerityDigest::Sha512(buf)),
+        _ => bail!("Unexpected hash size: {}", size),
     }
 }
 
-pub fn to_formatted_digest(digest: &Sha256Digest) -> FormattedSha256Digest {
-    let mut formatted_digest: FormattedSha256Digest = [0; FORMATTED_SHA256_DIGEST_SIZE];
-    formatted_digest[0..8].copy_from_slice(FS_VERITY_MAGIC);
-    formatted_digest[8..10].copy_from_slice(&(FS_VERITY_HASH_ALG_SHA256 as u16).to_le_bytes());
-    formatted_digest[10..12].copy_from_slice(&(SHA256_HASH_SIZE as u16).to_le_bytes());
-    formatted_digest[12..].copy_from_slice(digest);
+pub fn to_formatted_digest(digest: &FsVerityDigest) -> Vec<u8> {
+    let digest_bytes = digest.bytes();
+    let mut formatted_digest = Vec::with_capacity(FORMATTED_DIGEST_PREFIX_SIZE + digest_bytes.len());
+    formatted_digest.extend_from_slice(FS_VERITY_MAGIC);
+    formatted_digest.extend_from_slice(&(digest.alg_id() as u16).to_le_bytes());
+    formatted_digest.extend_from_slice(&(digest_bytes.len() as u16).to_le_bytes());
+    formatted_digest.extend_from_slice(digest_bytes);
     formatted_digest
 }
@@ -0,0 +1,515 @@
+// Copyright 2022, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Verification of a virtio-blk boot image against its AVB vbmeta metadata (including chained
+//! partitions) before any of its contents are trusted, mirroring the model `avbtool`/
+//! `sign_virt_apex` use to sign images offline.
+
+use crate::entry::RebootReason;
+use crate::virtio::hal::HalImpl;
+use alloc::vec;
+use alloc::vec::Vec;
+use log::{debug, error};
+use sha2::{Digest, Sha256};
+use virtio_drivers::device::blk::VirtIOBlk;
+use virtio_drivers::transport::pci::PciTransport;
+
+/// Sector size used by the virtio-blk backend.
+const BLOCK_SIZE: usize = 512;
+
+/// Magic bytes at the start of every `AvbVBMetaImageHeader`.
+const AVB_MAGIC: &[u8; 4] = b"AVB0";
+
+/// Size in bytes of the fixed-layout `AvbVBMetaImageHeader`.
+const VBMETA_HEADER_SIZE: usize = 256;
+
+/// Descriptor tag for `AvbHashDescriptor`.
+const AVB_DESCRIPTOR_TAG_HASH: u64 = 2;
+/// Descriptor tag for `AvbChainPartitionDescriptor`.
+const AVB_DESCRIPTOR_TAG_CHAIN_PARTITION: u64 = 4;
+/// Descriptor tag for `AvbHashtreeDescriptor`.
+const AVB_DESCRIPTOR_TAG_HASHTREE: u64 = 1;
+
+/// Recursion depth cap for chained vbmeta verification, guarding against a chain-partition cycle
+/// (or just a very long chain) exhausting the stack.
+const MAX_CHAIN_DEPTH: u32 = 8;
+
+/// Verifies a signature blob over `message` using `public_key`, per the algorithm identified by
+/// `algorithm_type` (an `AvbAlgorithmType` value from the vbmeta header).
+///
+/// This is pluggable because pvmfw delegates the actual RSA/ECDSA primitive to a verified crypto
+/// backend rather than re-implementing it here.
+pub trait PublicKeyVerifier {
+    fn verify(&self, algorithm_type: u32, message: &[u8], signature: &[u8], public_key: &[u8]) -> bool;
+}
+
+/// The minimum acceptable rollback index for each `rollback_index_location` that verification
+/// should enforce.
+pub struct RollbackPolicy<'a> {
+    pub minimum_by_location: &'a [(u32, u64)],
+}
+
+impl RollbackPolicy<'_> {
+    fn minimum_for(&self, location: u32) -> u64 {
+        self.minimum_by_location
+            .iter()
+            .find(|(loc, _)| *loc == location)
+            .map(|(_, min)| *min)
+            .unwrap_or(0)
+    }
+}
+
+/// Maps partition names to their absolute byte offset on `blk`, as found in whatever partition
+/// table (e.g. a GPT) the caller has already parsed. This module only consumes the result; it has
+/// no partition-table parsing of its own.
+pub struct PartitionTable<'a> {
+    pub offset_by_name: &'a [(&'a str, u64)],
+}
+
+impl PartitionTable<'_> {
+    fn offset_of(&self, partition_name: &str) -> Result<u64, RebootReason> {
+        self.offset_by_name
+            .iter()
+            .find(|(name, _)| *name == partition_name)
+            .map(|(_, offset)| *offset)
+            .ok_or_else(|| {
+                error!("No partition table entry for '{}'", partition_name);
+                RebootReason::InternalError
+            })
+    }
+}
+
+/// The 256-byte `AvbVBMetaImageHeader`. All multi-byte fields are big-endian, as laid out by
+/// `avb_vbmeta_image.h`.
+struct VbMetaHeader {
+    auxiliary_data_block_size: u64,
+    algorithm_type: u32,
+    hash_offset: u64,
+    hash_size: u64,
+    signature_offset: u64,
+    signature_size: u64,
+    public_key_offset: u64,
+    public_key_size: u64,
+    public_key_metadata_offset: u64,
+    public_key_metadata_size: u64,
+    descriptors_offset: u64,
+    descriptors_size: u64,
+    rollback_index: u64,
+    rollback_index_location: u32,
+}
+
+impl VbMetaHeader {
+    fn parse(bytes: &[u8]) -> Result<Self, RebootReason> {
+        if bytes.len() < VBMETA_HEADER_SIZE || &bytes[0..4] != AVB_MAGIC {
+            error!("vbmeta header is missing or has an invalid magic");
+            return Err(RebootReason::InternalError);
+        }
+        let be32 = |off: usize| u32::from_be_bytes(bytes[off..off + 4].try_into().unwrap());
+        let be64 = |off: usize| u64::from_be_bytes(bytes[off..off + 8].try_into().unwrap());
+        Ok(Self {
+            auxiliary_data_block_size: be64(20),
+            algorithm_type: be32(28),
+            hash_offset: be64(32),
+            hash_size: be64(40),
+            signature_offset: be64(48),
+            signature_size: be64(56),
+            public_key_offset: be64(64),
+            public_key_size: be64(72),
+            public_key_metadata_offset: be64(80),
+            public_key_metadata_size: be64(88),
+            descriptors_offset: be64(96),
+            descriptors_size: be64(104),
+            rollback_index: be64(112),
+            rollback_index_location: be32(124),
+        })
+    }
+}
+
+/// A parsed `AvbHashDescriptor`: verifies that a named partition's contents hash to `digest`.
+struct HashDescriptor {
+    image_size: u64,
+    partition_name: Vec<u8>,
+    salt: Vec<u8>,
+    digest: Vec<u8>,
+}
+
+/// A parsed `AvbChainPartitionDescriptor`: delegates trust for a named partition to the vbmeta
+/// image stored at its start, signed by the embedded public key.
+struct ChainPartitionDescriptor {
+    rollback_index_location: u32,
+    partition_name: Vec<u8>,
+    public_key: Vec<u8>,
+}
+
+enum Descriptor {
+    Hash(HashDescriptor),
+    ChainPartition(ChainPartitionDescriptor),
+    Other,
+}
+
+/// Reads `len` bytes starting at byte `offset` from the virtio-blk device, via block-aligned
+/// reads.
+fn read_bytes(
+    blk: &mut VirtIOBlk<HalImpl, PciTransport>,
+    offset: u64,
+    len: u64,
+) -> Result<Vec<u8>, RebootReason> {
+    let first_block = (offset / BLOCK_SIZE as u64) as usize;
+    let last_block = ((offset + len + BLOCK_SIZE as u64 - 1) / BLOCK_SIZE as u64) as usize;
+    let mut out = vec![0u8; (last_block - first_block) * BLOCK_SIZE];
+    for (i, chunk) in out.chunks_mut(BLOCK_SIZE).enumerate() {
+        blk.read_block(first_block + i, chunk).map_err(|e| {
+            error!("Failed to read block {}: {:?}", first_block + i, e);
+            RebootReason::InternalError
+        })?;
+    }
+    let start = (offset % BLOCK_SIZE as u64) as usize;
+    Ok(out[start..start + len as usize].to_vec())
+}
+
+/// Parses the descriptors in the auxiliary block, in the order they appear.
+fn parse_descriptors(mut bytes: &[u8]) -> Result<Vec<Descriptor>, RebootReason> {
+    let mut descriptors = Vec::new();
+    while bytes.len() >= 16 {
+        let tag = u64::from_be_bytes(bytes[0..8].try_into().unwrap());
+        let num_bytes_following = u64::from_be_bytes(bytes[8..16].try_into().unwrap()) as usize;
+        if bytes.len() < 16 + num_bytes_following {
+            error!("Truncated AVB descriptor");
+            return Err(RebootReason::InternalError);
+        }
+        let body = &bytes[16..16 + num_bytes_following];
+        descriptors.push(parse_descriptor(tag, body)?);
+        bytes = &bytes[16 + num_bytes_following..];
+    }
+    Ok(descriptors)
+}
+
+fn parse_descriptor(tag: u64, body: &[u8]) -> Result<Descriptor, RebootReason> {
+    match tag {
+        AVB_DESCRIPTOR_TAG_HASH => {
+            // AvbHashDescriptor: image_size(8) hash_algorithm[32] partition_name_len(4)
+            // salt_len(4) digest_len(4) flags(4) reserved[60], then the variable-length fields.
+            const FIXED_LEN: usize = 8 + 32 + 4 + 4 + 4 + 4 + 60;
+            if body.len() < FIXED_LEN {
+                error!("Truncated AVB hash descriptor");
+                return Err(RebootReason::InternalError);
+            }
+            let image_size = u64::from_be_bytes(body[0..8].try_into().unwrap());
+            let partition_name_len = u32::from_be_bytes(body[40..44].try_into().unwrap()) as usize;
+            let salt_len = u32::from_be_bytes(body[44..48].try_into().unwrap()) as usize;
+            let digest_len = u32::from_be_bytes(body[48..52].try_into().unwrap()) as usize;
+            let mut rest = &body[FIXED_LEN..];
+            let partition_name = take(&mut rest, partition_name_len)?;
+            let salt = take(&mut rest, salt_len)?;
+            let digest = take(&mut rest, digest_len)?;
+            Ok(Descriptor::Hash(HashDescriptor { image_size, partition_name, salt, digest }))
+        }
+        AVB_DESCRIPTOR_TAG_CHAIN_PARTITION => {
+            // AvbChainPartitionDescriptor: rollback_index_location(4) partition_name_len(4)
+            // public_key_len(4) reserved[64], then the variable-length fields.
+            const FIXED_LEN: usize = 4 + 4 + 4 + 64;
+            if body.len() < FIXED_LEN {
+                error!("Truncated AVB chain partition descriptor");
+                return Err(RebootReason::InternalError);
+            }
+            let rollback_index_location = u32::from_be_bytes(body[0..4].try_into().unwrap());
+            let partition_name_len = u32::from_be_bytes(body[4..8].try_into().unwrap()) as usize;
+            let public_key_len = u32::from_be_bytes(body[8..12].try_into().unwrap()) as usize;
+            let mut rest = &body[FIXED_LEN..];
+            let partition_name = take(&mut rest, partition_name_len)?;
+            let public_key = take(&mut rest, public_key_len)?;
+            Ok(Descriptor::ChainPartition(ChainPartitionDescriptor {
+                rollback_index_location,
+                partition_name,
+                public_key,
+            }))
+        }
+        AVB_DESCRIPTOR_TAG_HASHTREE => {
+            // TODO: set up dm-verity-style page hashing for hashtree descriptors. Until then, a
+            // vbmeta that relies on one for content verification must not be treated as verified.
+            error!("AvbHashtreeDescriptor verification is not implemented; refusing to trust it");
+            Err(RebootReason::InternalError)
+        }
+        _ => Ok(Descriptor::Other),
+    }
+}
+
+fn take(bytes: &mut &[u8], len: usize) -> Result<Vec<u8>, RebootReason> {
+    if bytes.len() < len {
+        error!("Truncated AVB descriptor field");
+        return Err(RebootReason::InternalError);
+    }
+    let (head, tail) = bytes.split_at(len);
+    *bytes = tail;
+    Ok(head.to_vec())
+}
+
+/// Verifies the vbmeta image starting at `vbmeta_offset` on `blk`, checking its signature against
+/// `public_key` (or the embedded key, for a self-signed top-level vbmeta), enforcing
+/// `rollback_policy`, and recursing into any chained partitions (looking each one's own vbmeta
+/// offset up in `partitions`). `partition_name` is used only for diagnostics.
+pub fn verify_vbmeta(
+    blk: &mut VirtIOBlk<HalImpl, PciTransport>,
+    partition_name: &str,
+    vbmeta_offset: u64,
+    public_key: Option<&[u8]>,
+    expected_rollback_index_location: Option<u32>,
+    rollback_policy: &RollbackPolicy,
+    partitions: &PartitionTable,
+    verifier: &dyn PublicKeyVerifier,
+) -> Result<(), RebootReason> {
+    verify_vbmeta_at_depth(
+        blk,
+        partition_name,
+        vbmeta_offset,
+        public_key,
+        expected_rollback_index_location,
+        rollback_policy,
+        partitions,
+        verifier,
+        0,
+    )
+}
+
+#[allow(clippy::too_many_arguments)]
+fn verify_vbmeta_at_depth(
+    blk: &mut VirtIOBlk<HalImpl, PciTransport>,
+    partition_name: &str,
+    vbmeta_offset: u64,
+    public_key: Option<&[u8]>,
+    expected_rollback_index_location: Option<u32>,
+    rollback_policy: &RollbackPolicy,
+    partitions: &PartitionTable,
+    verifier: &dyn PublicKeyVerifier,
+    depth: u32,
+) -> Result<(), RebootReason> {
+    if depth > MAX_CHAIN_DEPTH {
+        error!("vbmeta chain is more than {} partitions deep; refusing to recurse further", MAX_CHAIN_DEPTH);
+        return Err(RebootReason::InternalError);
+    }
+
+    let header_bytes = read_bytes(blk, vbmeta_offset, VBMETA_HEADER_SIZE as u64)?;
+    let header = VbMetaHeader::parse(&header_bytes)?;
+
+    // The authentication block directly follows the header; its size isn't one of the fields
+    // `VbMetaHeader` keeps, so read it straight out of the raw header bytes.
+    let authentication_block_size = u64::from_be_bytes(header_bytes[12..20].try_into().unwrap());
+    let auxiliary_block_offset = vbmeta_offset + VBMETA_HEADER_SIZE as u64 + authentication_block_size;
+
+    let aux_block = read_bytes(blk, auxiliary_block_offset, header.auxiliary_data_block_size)?;
+
+    if header.public_key_metadata_size > 0 {
+        debug!(
+            "vbmeta '{}' has public key metadata at offset {} (size {}); custom key matching is \
+             not implemented, the signature is only checked against the embedded/provided key",
+            partition_name, header.public_key_metadata_offset, header.public_key_metadata_size
+        );
+    }
+
+    if header.signature_size == 0 {
+        error!("vbmeta '{}' is unsigned; refusing to trust it", partition_name);
+        return Err(RebootReason::InternalError);
+    }
+
+    let embedded_public_key = &aux_block[header.public_key_offset as usize
+        ..(header.public_key_offset + header.public_key_size) as usize];
+    let trusted_key = public_key.unwrap_or(embedded_public_key);
+
+    let signature = read_bytes(
+        blk,
+        vbmeta_offset + VBMETA_HEADER_SIZE as u64 + header.signature_offset,
+        header.signature_size,
+    )?;
+    let expected_hash = read_bytes(
+        blk,
+        vbmeta_offset + VBMETA_HEADER_SIZE as u64 + header.hash_offset,
+        header.hash_size,
+    )?;
+
+    let mut hasher = Sha256::new();
+    hasher.update(&header_bytes);
+    hasher.update(&aux_block);
+    let actual_hash = hasher.finalize();
+    if actual_hash.as_slice() != expected_hash.as_slice() {
+        error!("vbmeta '{}' hash mismatch", partition_name);
+        return Err(RebootReason::InternalError);
+    }
+
+    if !verifier.verify(header.algorithm_type, &expected_hash, &signature, trusted_key) {
+        error!("vbmeta '{}' signature verification failed", partition_name);
+        return Err(RebootReason::InternalError);
+    }
+
+    if let Some(expected_location) = expected_rollback_index_location {
+        if header.rollback_index_location != expected_location {
+            error!(
+                "vbmeta '{}' rollback index location {} does not match the {} its chain \
+                 partition descriptor requires",
+                partition_name, header.rollback_index_location, expected_location
+            );
+            return Err(RebootReason::InternalError);
+        }
+    }
+
+    if header.rollback_index < rollback_policy.minimum_for(header.rollback_index_location) {
+        error!("vbmeta '{}' rollback index {} is below the minimum", partition_name, header.rollback_index);
+        return Err(RebootReason::InternalError);
+    }
+
+    let descriptors_offset = auxiliary_block_offset + header.descriptors_offset;
+    let descriptors = parse_descriptors(&read_bytes(blk, descriptors_offset, header.descriptors_size)?)?;
+
+    for descriptor in descriptors {
+        match descriptor {
+            Descriptor::Hash(hash) => verify_hash_descriptor(blk, &hash, partitions)?,
+            Descriptor::ChainPartition(chain) => verify_chain_partition(
+                blk,
+                &chain,
+                rollback_policy,
+                partitions,
+                verifier,
+                depth + 1,
+            )?,
+            Descriptor::Other => {}
+        }
+    }
+
+    Ok(())
+}
+
+/// Hashes the named partition's bytes (as found by looking it up in `partitions`) and compares
+/// against the descriptor's salted digest.
+fn verify_hash_descriptor(
+    blk: &mut VirtIOBlk<HalImpl, PciTransport>,
+    descriptor: &HashDescriptor,
+    partitions: &PartitionTable,
+) -> Result<(), RebootReason> {
+    let partition_name = core::str::from_utf8(&descriptor.partition_name).unwrap_or("<invalid>");
+    let partition_offset = partitions.offset_of(partition_name)?;
+    let image = read_bytes(blk, partition_offset, descriptor.image_size)?;
+
+    let mut hasher = Sha256::new();
+    hasher.update(&descriptor.salt);
+    hasher.update(&image);
+    let actual_digest = hasher.finalize();
+
+    if actual_digest.as_slice() != descriptor.digest.as_slice() {
+        error!("Hash mismatch for partition '{}'", partition_name);
+        return Err(RebootReason::InternalError);
+    }
+    Ok(())
+}
+
+/// Loads and recursively verifies the vbmeta image of a chained partition (found by looking its
+/// name up in `partitions`), trusting only the public key embedded in the parent's
+/// chain-partition descriptor.
+#[allow(clippy::too_many_arguments)]
+fn verify_chain_partition(
+    blk: &mut VirtIOBlk<HalImpl, PciTransport>,
+    descriptor: &ChainPartitionDescriptor,
+    rollback_policy: &RollbackPolicy,
+    partitions: &PartitionTable,
+    verifier: &dyn PublicKeyVerifier,
+    depth: u32,
+) -> Result<(), RebootReason> {
+    let partition_name = core::str::from_utf8(&descriptor.partition_name).unwrap_or("<invalid>");
+    let vbmeta_offset = partitions.offset_of(partition_name)?;
+    verify_vbmeta_at_depth(
+        blk,
+        partition_name,
+        vbmeta_offset,
+        Some(&descriptor.public_key),
+        Some(descriptor.rollback_index_location),
+        rollback_policy,
+        partitions,
+        verifier,
+        depth,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Builds a zeroed 256-byte `AvbVBMetaImageHeader`-shaped buffer with the given field values
+    /// poked in at their real offsets, per `avb_vbmeta_image.h`.
+    fn build_header() -> Vec<u8> {
+        let mut bytes = vec![0u8; VBMETA_HEADER_SIZE];
+        bytes[0..4].copy_from_slice(AVB_MAGIC);
+        bytes[20..28].copy_from_slice(&7u64.to_be_bytes()); // auxiliary_data_block_size
+        bytes[28..32].copy_from_slice(&1u32.to_be_bytes()); // algorithm_type
+        bytes[32..40].copy_from_slice(&10u64.to_be_bytes()); // hash_offset
+        bytes[40..48].copy_from_slice(&32u64.to_be_bytes()); // hash_size
+        bytes[48..56].copy_from_slice(&50u64.to_be_bytes()); // signature_offset
+        bytes[56..64].copy_from_slice(&256u64.to_be_bytes()); // signature_size
+        bytes[64..72].copy_from_slice(&0u64.to_be_bytes()); // public_key_offset
+        bytes[72..80].copy_from_slice(&8u64.to_be_bytes()); // public_key_size
+        bytes[80..88].copy_from_slice(&8u64.to_be_bytes()); // public_key_metadata_offset
+        bytes[88..96].copy_from_slice(&0u64.to_be_bytes()); // public_key_metadata_size
+        bytes[96..104].copy_from_slice(&8u64.to_be_bytes()); // descriptors_offset
+        bytes[104..112].copy_from_slice(&24u64.to_be_bytes()); // descriptors_size
+        bytes[112..120].copy_from_slice(&42u64.to_be_bytes()); // rollback_index
+        bytes[124..128].copy_from_slice(&3u32.to_be_bytes()); // rollback_index_location
+        bytes
+    }
+
+    #[test]
+    fn parses_header_fields_at_their_real_offsets() {
+        let header = VbMetaHeader::parse(&build_header()).unwrap();
+        assert_eq!(header.auxiliary_data_block_size, 7);
+        assert_eq!(header.algorithm_type, 1);
+        assert_eq!(header.hash_offset, 10);
+        assert_eq!(header.hash_size, 32);
+        assert_eq!(header.signature_offset, 50);
+        assert_eq!(header.signature_size, 256);
+        assert_eq!(header.public_key_offset, 0);
+        assert_eq!(header.public_key_size, 8);
+        assert_eq!(header.public_key_metadata_offset, 8);
+        assert_eq!(header.public_key_metadata_size, 0);
+        assert_eq!(header.descriptors_offset, 8);
+        assert_eq!(header.descriptors_size, 24);
+        assert_eq!(header.rollback_index, 42);
+        assert_eq!(header.rollback_index_location, 3);
+    }
+
+    #[test]
+    fn rejects_missing_magic() {
+        let mut bytes = build_header();
+        bytes[0..4].copy_from_slice(b"NOPE");
+        assert!(VbMetaHeader::parse(&bytes).is_err());
+    }
+
+    #[test]
+    fn parses_hash_descriptor_body() {
+        // image_size(8) hash_algorithm[32] partition_name_len(4) salt_len(4) digest_len(4)
+        // flags(4) reserved[60], then the variable-length fields.
+        let mut body = vec![0u8; 8 + 32 + 4 + 4 + 4 + 4 + 60];
+        body[0..8].copy_from_slice(&1234u64.to_be_bytes());
+        body[40..44].copy_from_slice(&4u32.to_be_bytes());
+        body[44..48].copy_from_slice(&2u32.to_be_bytes());
+        body[48..52].copy_from_slice(&3u32.to_be_bytes());
+        body.extend_from_slice(b"boot");
+        body.extend_from_slice(&[0xaa, 0xbb]);
+        body.extend_from_slice(&[1, 2, 3]);
+
+        let Descriptor::Hash(hash) = parse_descriptor(AVB_DESCRIPTOR_TAG_HASH, &body).unwrap()
+        else {
+            panic!("expected a hash descriptor");
+        };
+        assert_eq!(hash.image_size, 1234);
+        assert_eq!(hash.partition_name, b"boot");
+        assert_eq!(hash.salt, [0xaa, 0xbb]);
+        assert_eq!(hash.digest, [1, 2, 3]);
+    }
+}
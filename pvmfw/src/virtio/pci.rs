@@ -16,15 +16,165 @@
 
 use super::hal::HalImpl;
 use crate::{entry::RebootReason, memory::MemoryTracker};
+use alloc::vec::Vec;
+use core::ptr::addr_of_mut;
 use fdtpci::{PciError, PciInfo};
 use log::{debug, error, info};
 use virtio_drivers::{
     device::blk::VirtIOBlk,
+    device::console::VirtIOConsole,
+    device::net::VirtIONet,
     transport::{
-        pci::{bus::PciRoot, virtio_device_type, PciTransport},
+        pci::{bus::{DeviceFunction, PciRoot}, virtio_device_type, PciTransport},
         DeviceType, Transport,
     },
 };
+use vmbase::console::{set_console, ConsoleSink};
+
+/// Feature bit indicating that the device has a valid MAC address.
+const VIRTIO_NET_F_MAC: u64 = 1 << 5;
+/// Feature bit indicating that the device can receive merged RX buffers.
+const VIRTIO_NET_F_MRG_RXBUF: u64 = 1 << 15;
+/// Feature bit indicating that the device reports link status via `virtio_net_config::status`.
+const VIRTIO_NET_F_STATUS: u64 = 1 << 16;
+
+/// Number of bytes used for each RX/TX buffer of the virtio-net device.
+const NET_BUFFER_LEN: usize = 1536;
+/// Number of descriptors to allocate for each of the RX and TX virtqueues.
+const NET_QUEUE_SIZE: usize = 16;
+
+/// A VirtIO device discovered on the PCI bus, not yet turned into a typed driver.
+///
+/// This lets a caller inspect what's on the bus (and decide, e.g., which of several block devices
+/// to boot from) before paying the cost of driver setup.
+///
+/// Feature negotiation itself isn't controllable from here: each typed driver's own constructor
+/// (e.g. `VirtIOBlk::new`) drives `Transport::begin_init`, which resets the device and negotiates
+/// its own accepted feature subset before `DRIVER_OK`. Anything written to the transport before
+/// that point is discarded by the reset, so `device_features` below is exposed for diagnostic use
+/// only.
+pub struct DiscoveredDevice {
+    pub device_function: DeviceFunction,
+    pub device_type: DeviceType,
+    pub device_features: u64,
+    transport: PciTransport,
+}
+
+impl DiscoveredDevice {
+    /// Constructs a virtio-blk driver for this device.
+    pub fn into_block(self) -> Result<VirtIOBlk<HalImpl, PciTransport>, PciError> {
+        VirtIOBlk::<HalImpl, _>::new(self.transport).map_err(|e| {
+            error!("Failed to create virtio-blk driver: {:?}", e);
+            PciError::Capability
+        })
+    }
+
+    /// Constructs a virtio-net driver for this device.
+    pub fn into_net(self) -> Result<VirtioNetDevice, PciError> {
+        VirtioNetDevice::new(self.transport).ok_or(PciError::Capability)
+    }
+
+    /// Constructs a virtio-console driver for this device.
+    pub fn into_console(self) -> Result<VirtioConsoleDevice, PciError> {
+        VirtioConsoleDevice::new(self.transport).ok_or(PciError::Capability)
+    }
+}
+
+/// A discovered virtio-net PCI device, ready to send and receive Ethernet frames.
+pub struct VirtioNetDevice {
+    net: VirtIONet<HalImpl, PciTransport, NET_QUEUE_SIZE>,
+}
+
+impl VirtioNetDevice {
+    /// Constructs the driver from `transport`, which negotiates the device's features as part of
+    /// initialisation, or returns `None` if initialisation fails.
+    fn new(transport: PciTransport) -> Option<Self> {
+        let device_features = transport.read_device_features();
+        info!(
+            "Found virtio-net device, MAC feature={}, MRG_RXBUF feature={}, STATUS feature={}",
+            device_features & VIRTIO_NET_F_MAC != 0,
+            device_features & VIRTIO_NET_F_MRG_RXBUF != 0,
+            device_features & VIRTIO_NET_F_STATUS != 0,
+        );
+        let net = match VirtIONet::<HalImpl, _, NET_QUEUE_SIZE>::new(transport, NET_BUFFER_LEN) {
+            Ok(net) => net,
+            Err(e) => {
+                error!("Failed to initialise virtio-net device: {:?}", e);
+                return None;
+            }
+        };
+        // Only the STATUS feature bit is logged above; the device's link status field is not read.
+        // `can_send` reports whether the TX virtqueue has room for another frame.
+        info!("virtio-net MAC address: {:?}, can send: {}", net.mac_address(), net.can_send());
+        Some(Self { net })
+    }
+
+    /// Returns the MAC address negotiated with the device.
+    pub fn mac_address(&self) -> [u8; 6] {
+        self.net.mac_address()
+    }
+
+    /// Sends a single raw Ethernet frame over the TX virtqueue.
+    pub fn send(&mut self, frame: &[u8]) -> Result<(), PciError> {
+        self.net.send(frame).map_err(|e| {
+            error!("Failed to send packet over virtio-net: {:?}", e);
+            PciError::Capability
+        })
+    }
+
+    /// Receives a single raw Ethernet frame from the RX virtqueue, if one is available.
+    pub fn receive<'a>(&mut self, buffer: &'a mut [u8]) -> Result<&'a mut [u8], PciError> {
+        self.net.receive(buffer).map_err(|e| {
+            error!("Failed to receive packet over virtio-net: {:?}", e);
+            PciError::Capability
+        })
+    }
+}
+
+/// A discovered virtio-console PCI device, used to redirect the bootloader's `println!` output.
+pub struct VirtioConsoleDevice {
+    console: VirtIOConsole<HalImpl, PciTransport>,
+}
+
+impl VirtioConsoleDevice {
+    /// Constructs the driver from `transport`, which negotiates the device's features as part of
+    /// initialisation, or returns `None` if initialisation fails.
+    fn new(transport: PciTransport) -> Option<Self> {
+        match VirtIOConsole::<HalImpl, _>::new(transport) {
+            Ok(console) => Some(Self { console }),
+            Err(e) => {
+                error!("Failed to initialise virtio-console device: {:?}", e);
+                None
+            }
+        }
+    }
+
+    /// Installs this device as the sink for the bootloader's `println!` output.
+    ///
+    /// This leaks the device into a `'static` slot, since `vmbase::console::set_console` needs
+    /// its sink to outlive the rest of the boot process.
+    pub fn install_as_console(self) {
+        // SAFETY: `find_virtio_devices` and its callers run once, to completion, on a single core
+        // before any other code touches `CONSOLE_DEVICE` or calls `vmbase::console::set_console`.
+        // Going through `addr_of_mut!` rather than naming `CONSOLE_DEVICE` in a `&mut` expression
+        // avoids ever materialising more than one live reference to it, which is what
+        // `static_mut_refs` warns about.
+        let console = unsafe { (*addr_of_mut!(CONSOLE_DEVICE)).insert(self) };
+        set_console(console);
+    }
+}
+
+impl ConsoleSink for VirtioConsoleDevice {
+    fn write_bytes(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            let _ = self.console.send(byte);
+        }
+    }
+}
+
+/// Storage for the virtio-console device, which must outlive `find_virtio_devices` once it has
+/// been handed to `vmbase::console::set_console`.
+static mut CONSOLE_DEVICE: Option<VirtioConsoleDevice> = None;
 
 /// Maps the CAM and BAR range in the page table and MMIO guard.
 pub fn map_mmio(pci_info: &PciInfo, memory: &mut MemoryTracker) -> Result<(), RebootReason> {
@@ -43,31 +193,33 @@ pub fn map_mmio(pci_info: &PciInfo, memory: &mut MemoryTracker) -> Result<(), Re
     Ok(())
 }
 
-/// Finds VirtIO PCI devices.
-pub fn find_virtio_devices(pci_root: &mut PciRoot) -> Result<(), PciError> {
+/// Scans the PCI bus for VirtIO devices, returning the inventory of everything found. Each entry
+/// still needs to be turned into a typed driver (see [`DiscoveredDevice::into_block`] and
+/// friends) before use.
+pub fn find_virtio_devices(pci_root: &mut PciRoot) -> Result<Vec<DiscoveredDevice>, PciError> {
+    let mut devices = Vec::new();
+
     for (device_function, info) in pci_root.enumerate_bus(0) {
         let (status, command) = pci_root.get_status_command(device_function);
         debug!(
             "Found PCI device {} at {}, status {:?} command {:?}",
             info, device_function, status, command
         );
-        if let Some(virtio_type) = virtio_device_type(&info) {
-            debug!("  VirtIO {:?}", virtio_type);
-            let mut transport = PciTransport::new::<HalImpl>(pci_root, device_function).unwrap();
-            info!(
-                "Detected virtio PCI device with device type {:?}, features {:#018x}",
-                transport.device_type(),
-                transport.read_device_features(),
-            );
-            if virtio_type == DeviceType::Block {
-                let mut blk =
-                    VirtIOBlk::<HalImpl, _>::new(transport).expect("failed to create blk driver");
-                info!("Found {} KiB block device.", blk.capacity() * 512 / 1024);
-                let mut data = [0; 512];
-                blk.read_block(0, &mut data).expect("Failed to read block device");
-            }
-        }
+        let Some(device_type) = virtio_device_type(&info) else {
+            continue;
+        };
+        debug!("  VirtIO {:?}", device_type);
+        let transport = PciTransport::new::<HalImpl>(pci_root, device_function).map_err(|e| {
+            error!("Failed to create PCI transport for {}: {:?}", device_function, e);
+            e
+        })?;
+        let device_features = transport.read_device_features();
+        info!(
+            "Detected virtio PCI device with device type {:?}, features {:#018x}",
+            device_type, device_features,
+        );
+        devices.push(DiscoveredDevice { device_function, device_type, device_features, transport });
     }
 
-    Ok(())
-}
\ No newline at end of file
+    Ok(devices)
+}
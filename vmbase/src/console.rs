@@ -0,0 +1,66 @@
+// Copyright 2022, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Support for redirecting `println!` output to a device discovered at runtime, such as a
+//! virtio-console. Until one is installed, [`try_write_fmt`] returns `false` so that a caller can
+//! use its own output path; vmbase's own `print!`/`println!` discard the output in that case.
+
+use core::fmt;
+use core::ptr::addr_of_mut;
+
+/// A destination for the bytes written by the `println!` macro.
+pub trait ConsoleSink {
+    /// Writes `bytes` to the console.
+    fn write_bytes(&mut self, bytes: &[u8]);
+}
+
+/// The console that `println!` writes to once it has been installed via [`set_console`].
+///
+/// # Safety
+///
+/// Like the rest of vmbase's early boot state (see `vmbase/example/src/main.rs`), this relies on
+/// firmware running on a single core with a single thread of execution, so plain mutable-static
+/// access is sound here.
+static mut CONSOLE: Option<&'static mut dyn ConsoleSink> = None;
+
+/// Redirects subsequent `println!` output to `sink`. Until this is called, vmbase's `print!`/
+/// `println!` output is discarded, and [`try_write_fmt`] returns `false`.
+pub fn set_console(sink: &'static mut dyn ConsoleSink) {
+    // SAFETY: See the comment on `CONSOLE`. Going through `addr_of_mut!` rather than naming
+    // `CONSOLE` in a `&mut` expression avoids ever materialising more than one live reference to
+    // it, which is what `static_mut_refs` warns about.
+    unsafe { *addr_of_mut!(CONSOLE) = Some(sink) };
+}
+
+/// Writes `args` to the installed console, if any, returning whether it was handled. If this
+/// returns `false`, the caller should fall back to its default output path.
+pub fn try_write_fmt(args: fmt::Arguments) -> bool {
+    // SAFETY: See the comment on `CONSOLE`, and on `set_console` for why this goes through
+    // `addr_of_mut!`.
+    let console = unsafe { &mut *addr_of_mut!(CONSOLE) };
+    let Some(console) = console.as_deref_mut() else {
+        return false;
+    };
+    let _ = fmt::Write::write_fmt(&mut SinkWriter(console), args);
+    true
+}
+
+struct SinkWriter<'a>(&'a mut dyn ConsoleSink);
+
+impl fmt::Write for SinkWriter<'_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.0.write_bytes(s.as_bytes());
+        Ok(())
+    }
+}
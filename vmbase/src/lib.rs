@@ -0,0 +1,43 @@
+// Copyright 2022, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Library for VM bootloaders.
+
+#![no_std]
+
+extern crate alloc;
+
+pub mod console;
+pub mod memory;
+
+use core::fmt;
+
+#[doc(hidden)]
+pub fn _print(args: fmt::Arguments) {
+    let _ = console::try_write_fmt(args);
+}
+
+/// Prints to the installed console (see [`console::set_console`]), formatted as by [`format!`].
+/// Until a console has been installed, this has no effect.
+#[macro_export]
+macro_rules! print {
+    ($($arg:tt)*) => ($crate::_print(core::format_args!($($arg)*)));
+}
+
+/// Like [`print!`], but appends a trailing newline.
+#[macro_export]
+macro_rules! println {
+    () => ($crate::print!("\n"));
+    ($($arg:tt)*) => ($crate::print!("{}\n", core::format_args!($($arg)*)));
+}
@@ -16,8 +16,11 @@
 
 use crate::read_sysreg;
 use aarch64_paging::idmap::IdMap;
-use aarch64_paging::paging::{Attributes, MemoryRegion, PteUpdater};
+use aarch64_paging::linearmap::LinearMap;
+use aarch64_paging::paging::{Attributes, Constraints, Descriptor, MemoryRegion, PteUpdater};
 use aarch64_paging::MapError;
+use core::arch::asm;
+use core::cell::Cell;
 use core::{ops::Range, result};
 
 /// Software bit used to indicate a device that should be lazily mapped.
@@ -77,15 +80,46 @@ impl PageTable {
 
     /// Activates the page table.
     ///
+    /// `written_range` must cover every virtual address this `PageTable` was used to map (or
+    /// modify) while the MMU was off, so its descriptors aren't necessarily cacheable yet; this
+    /// cleans that range to the point of coherency (see [`Self::clean_to_poc`]) before flipping
+    /// `TTBR0_EL1`, so the table walker is guaranteed to see the up to date descriptors.
+    ///
     /// # Safety
     ///
     /// The caller must ensure that the PageTable instance has valid and identical mappings for the
     /// code being currently executed. Otherwise, the Rust execution model (on which the borrow
     /// checker relies) would be violated.
-    pub unsafe fn activate(&mut self) {
+    pub unsafe fn activate(&mut self, written_range: &Range<usize>) {
+        Self::clean_to_poc(written_range);
         self.idmap.activate()
     }
 
+    /// Cleans the given range of virtual addresses to the point of coherency (DC CVAC), so that
+    /// writes made with the MMU off (and hence not necessarily cacheable) become visible to other
+    /// observers such as the hardware table walker.
+    pub fn clean_to_poc(range: &Range<usize>) {
+        dcache_range_op(range, dc_cvac);
+    }
+
+    /// Cleans and invalidates the given range of virtual addresses to the point of coherency
+    /// (DC CIVAC), so that stale cachelines can no longer obscure or corrupt the underlying
+    /// memory.
+    pub fn clean_and_invalidate_to_poc(range: &Range<usize>) {
+        dcache_range_op(range, dc_civac);
+    }
+
+    /// Invalidates the whole instruction cache (IC IALLU), e.g. after writing code that the MMU
+    /// was off for, so the core doesn't execute stale instructions fetched from before the write.
+    pub fn invalidate_icache_all() {
+        // SAFETY: IC IALLU invalidates the entire instruction cache; it doesn't affect memory
+        // safety, and the following DSB/ISB ensure the invalidation is complete and visible to
+        // subsequently fetched instructions.
+        unsafe {
+            asm!("ic iallu", "dsb sy", "isb", options(nostack));
+        }
+    }
+
     /// Maps the given range of virtual addresses to the physical addresses as lazily mapped
     /// nGnRE device memory.
     pub fn map_device_lazy(&mut self, range: &Range<usize>) -> Result<()> {
@@ -98,6 +132,21 @@ impl PageTable {
         self.map_range(range, DEVICE)
     }
 
+    /// Maps the given range of virtual addresses to the physical addresses as valid device
+    /// nGnRE device memory, forcing page-granularity descriptors with no contiguous hint.
+    ///
+    /// Block and contiguous descriptors coalesce TLB entries (and, on some implementations,
+    /// speculative accesses) across the whole mapped region, which is unsafe for MMIO windows
+    /// whose individual registers have different access requirements. Use this instead of
+    /// [`Self::map_device`]/[`Self::map_device_lazy`] when that matters.
+    pub fn map_device_pages(&mut self, range: &Range<usize>) -> Result<()> {
+        self.idmap.map_range_with_constraints(
+            &MemoryRegion::new(range.start, range.end),
+            DEVICE,
+            Constraints::NO_BLOCK_MAPPINGS | Constraints::NO_CONTIGUOUS_MAPPINGS,
+        )
+    }
+
     /// Maps the given range of virtual addresses to the physical addresses as non-executable
     /// and writable normal memory.
     pub fn map_data(&mut self, range: &Range<usize>) -> Result<()> {
@@ -129,10 +178,210 @@ impl PageTable {
     }
 
     /// Applies the provided updater function to a number of PTEs corresponding to a given memory
-    /// range.
+    /// range, first splitting any block descriptor that `range` only partially covers down to the
+    /// next translation level, so the update can be scoped to exactly `range`.
     pub fn modify_range(&mut self, range: &Range<usize>, f: &PteUpdater) -> Result<()> {
+        self.split_blocks_straddling(range)?;
         self.idmap.modify_range(&MemoryRegion::new(range.start, range.end), f)
     }
+
+    /// For each block-capable level, splits any block descriptor that `range` only partially
+    /// overlaps into a next-level table whose entries share the block's attributes and output
+    /// address, using the architectural break-before-make sequence.
+    fn split_blocks_straddling(&mut self, range: &Range<usize>) -> Result<()> {
+        for level in [1, 2] {
+            let block_size = block_size_at_level(level);
+            let mut block_start = range.start & !(block_size - 1);
+            while block_start < range.end {
+                let block_range = block_start..block_start + block_size;
+                if range.start > block_range.start || range.end < block_range.end {
+                    self.split_block(&block_range, level)?;
+                }
+                block_start = block_range.end;
+            }
+        }
+        Ok(())
+    }
+
+    /// Splits the block descriptor covering exactly `block_range` (at `level`) by marking it
+    /// invalid, flushing any stale TLB entries for the range, then remapping it with its original
+    /// attributes so it's backed by a next-level table instead of a single block.
+    fn split_block(&mut self, block_range: &Range<usize>, level: usize) -> Result<()> {
+        let block_attrs = Cell::new(None);
+        let region = MemoryRegion::new(block_range.start, block_range.end);
+        self.idmap.modify_range(&region, &|descriptor, pte_level| {
+            if pte_level == level && is_leaf_pte(&descriptor.flags(), pte_level) {
+                block_attrs.set(Some(descriptor.flags()));
+                descriptor.set_invalid();
+            }
+            Ok(())
+        })?;
+
+        let Some(attrs) = block_attrs.get() else {
+            // Not currently a block at this level (already split, or not mapped); nothing to do.
+            return Ok(());
+        };
+
+        // Break-before-make: the descriptor above is now invalid, so flush any TLB entries caching
+        // the old block before a new table is installed in its place.
+        tlb_invalidate_range(block_range);
+
+        // Remap the block one next-level child at a time with the block's original attributes: no
+        // single child covers the whole block, so `map_range` installs a next-level table in its
+        // place, whose descriptors together are equivalent to the block we replaced.
+        let child_size = leaf_size_at_level(level + 1);
+        let mut child_start = block_range.start;
+        while child_start < block_range.end {
+            self.map_range(&(child_start..child_start + child_size), attrs)?;
+            child_start += child_size;
+        }
+        Ok(())
+    }
+
+    /// Calls `visitor` once for each leaf mapping in `range` whose `Attributes::DBM` bit is set but
+    /// whose `Attributes::READ_ONLY` bit has been cleared by hardware — i.e. every mapping written
+    /// to since `range` was last armed via [`Self::map_data_dbm`] or [`Self::rearm_dirty_tracking`].
+    ///
+    /// This reports ranges via callback rather than building a `Vec<Range<usize>>`, so that this
+    /// otherwise allocation-free page table wrapper doesn't need to depend on `alloc`.
+    pub fn collect_dirty_ranges(
+        &mut self,
+        range: &Range<usize>,
+        visitor: &mut dyn FnMut(Range<usize>),
+    ) -> Result<()> {
+        let next_va = Cell::new(range.start);
+        self.modify_range(range, &|descriptor, level| {
+            let size = leaf_size_at_level(level);
+            let pte_range = next_va.get()..next_va.get() + size;
+            next_va.set(pte_range.end);
+
+            let flags = descriptor.flags();
+            if is_leaf_pte(&flags, level)
+                && flags.contains(Attributes::DBM)
+                && !flags.contains(Attributes::READ_ONLY)
+            {
+                visitor(pte_range);
+            }
+            Ok(())
+        })
+    }
+
+    /// Re-arms dirty tracking for every `Attributes::DBM` mapping in `range` that hardware has
+    /// cleared the read-only bit of, so that a subsequent write is needed before it shows up again
+    /// in [`Self::collect_dirty_ranges`].
+    pub fn rearm_dirty_tracking(&mut self, range: &Range<usize>) -> Result<()> {
+        self.modify_range(range, &|descriptor, level| {
+            let flags = descriptor.flags();
+            if is_leaf_pte(&flags, level)
+                && flags.contains(Attributes::DBM)
+                && !flags.contains(Attributes::READ_ONLY)
+            {
+                descriptor.modify_flags(Attributes::READ_ONLY, Attributes::empty());
+            }
+            Ok(())
+        })?;
+        tlb_invalidate_range(range);
+        Ok(())
+    }
+}
+
+/// High-level API for managing MMU mappings where the virtual address differs from the physical
+/// address by a fixed offset, e.g. for loading a payload into DRAM at an address distinct from
+/// where firmware will address it.
+///
+/// This shares [`PageTable`]'s `Attributes` presets and [`is_leaf_pte`] logic, but wraps
+/// [`LinearMap`] instead of [`IdMap`], so it does not offer the block-splitting or dirty-tracking
+/// helpers built on top of `IdMap::modify_range`.
+pub struct LinearPageTable {
+    linear: LinearMap,
+}
+
+impl LinearPageTable {
+    /// Creates a new linear-mapped page table at the given root level, translating accesses to
+    /// virtual address `va` to physical address `va + pa_offset`.
+    pub fn new(asid: usize, rootlevel: usize, pa_offset: isize) -> Self {
+        Self { linear: LinearMap::new(asid, rootlevel, pa_offset) }
+    }
+
+    /// Activates the page table.
+    ///
+    /// `written_range` must cover every virtual address this `LinearPageTable` was used to map (or
+    /// modify) while the MMU was off, so its descriptors aren't necessarily cacheable yet; this
+    /// cleans that range to the point of coherency (see [`PageTable::clean_to_poc`]) before
+    /// flipping `TTBR0_EL1`, so the table walker is guaranteed to see the up to date descriptors.
+    ///
+    /// # Safety
+    ///
+    /// The caller must ensure that the LinearPageTable instance has valid and identical mappings
+    /// for the code being currently executed. Otherwise, the Rust execution model (on which the
+    /// borrow checker relies) would be violated.
+    pub unsafe fn activate(&mut self, written_range: &Range<usize>) {
+        PageTable::clean_to_poc(written_range);
+        self.linear.activate()
+    }
+
+    /// Maps the given range of virtual addresses to the corresponding physical addresses as
+    /// lazily mapped nGnRE device memory.
+    pub fn map_device_lazy(&mut self, range: &Range<usize>) -> Result<()> {
+        self.map_range(range, DEVICE_LAZY)
+    }
+
+    /// Maps the given range of virtual addresses to the corresponding physical addresses as valid
+    /// device nGnRE device memory.
+    pub fn map_device(&mut self, range: &Range<usize>) -> Result<()> {
+        self.map_range(range, DEVICE)
+    }
+
+    /// Maps the given range of virtual addresses to the corresponding physical addresses as valid
+    /// device nGnRE device memory, forcing page-granularity descriptors with no contiguous hint.
+    ///
+    /// See [`PageTable::map_device_pages`] for why this matters for MMIO.
+    pub fn map_device_pages(&mut self, range: &Range<usize>) -> Result<()> {
+        self.linear.map_range_with_constraints(
+            &MemoryRegion::new(range.start, range.end),
+            DEVICE,
+            Constraints::NO_BLOCK_MAPPINGS | Constraints::NO_CONTIGUOUS_MAPPINGS,
+        )
+    }
+
+    /// Maps the given range of virtual addresses to the corresponding physical addresses as
+    /// non-executable and writable normal memory.
+    pub fn map_data(&mut self, range: &Range<usize>) -> Result<()> {
+        self.map_range(range, DATA)
+    }
+
+    /// Maps the given range of virtual addresses to the corresponding physical addresses as
+    /// non-executable, read-only and writable-clean normal memory.
+    pub fn map_data_dbm(&mut self, range: &Range<usize>) -> Result<()> {
+        self.map_range(range, DATA_DBM)
+    }
+
+    /// Maps the given range of virtual addresses to the corresponding physical addresses as
+    /// read-only normal memory.
+    pub fn map_code(&mut self, range: &Range<usize>) -> Result<()> {
+        self.map_range(range, CODE)
+    }
+
+    /// Maps the given range of virtual addresses to the corresponding physical addresses as
+    /// non-executable and read-only normal memory.
+    pub fn map_rodata(&mut self, range: &Range<usize>) -> Result<()> {
+        self.map_range(range, RODATA)
+    }
+
+    /// Maps the given range of virtual addresses to the corresponding physical addresses with the
+    /// given attributes.
+    fn map_range(&mut self, range: &Range<usize>, attr: Attributes) -> Result<()> {
+        self.linear.map_range(&MemoryRegion::new(range.start, range.end), attr)
+    }
+
+    /// Applies the provided updater function to every PTE corresponding to a given memory range.
+    ///
+    /// Unlike [`PageTable::modify_range`], this does not split block descriptors that `range`
+    /// only partially covers first; callers whose range may straddle a block boundary should map
+    /// at page granularity instead.
+    pub fn modify_range(&mut self, range: &Range<usize>, f: &PteUpdater) -> Result<()> {
+        self.linear.modify_range(&MemoryRegion::new(range.start, range.end), f)
+    }
 }
 
 /// Checks whether a PTE at given level is a page or block descriptor.
@@ -144,4 +393,94 @@ pub(super) fn is_leaf_pte(flags: &Attributes, level: usize) -> bool {
     } else {
         level < LEAF_PTE_LEVEL
     }
+}
+
+/// Returns the size in bytes of a single block descriptor at the given translation table level,
+/// for a 4 KiB granule.
+fn block_size_at_level(level: usize) -> usize {
+    match level {
+        1 => 1 << 30, // 1 GiB
+        2 => 1 << 21, // 2 MiB
+        _ => unreachable!("level {} has no block descriptors", level),
+    }
+}
+
+/// Returns the size in bytes of the leaf mapping (page or block) at the given translation table
+/// level, for a 4 KiB granule.
+fn leaf_size_at_level(level: usize) -> usize {
+    const PAGE_SIZE: usize = 1 << 12;
+    match level {
+        3 => PAGE_SIZE,
+        _ => block_size_at_level(level),
+    }
+}
+
+/// Invalidates the TLB entries for every page in `range`, for this page table's ASID.
+fn tlb_invalidate_range(range: &Range<usize>) {
+    const PAGE_SIZE: usize = 4096;
+
+    let mut va = range.start & !(PAGE_SIZE - 1);
+    while va < range.end {
+        let arg = (PageTable::ASID << 48) | (va >> 12);
+        // SAFETY: TLBI VAE1 only invalidates TLB entries for the given address and ASID; it
+        // cannot by itself violate memory safety.
+        unsafe { asm!("tlbi vae1, {0}", in(reg) arg, options(nostack)) };
+        va += PAGE_SIZE;
+    }
+
+    // SAFETY: DSB/ISB synchronise the invalidations above so they are guaranteed complete before
+    // the caller installs the replacement descriptor.
+    unsafe { asm!("dsb ish", "isb", options(nostack)) };
+}
+
+/// Returns the minimum D-cache line size in bytes, read from the `DminLine` field of `CTR_EL0`.
+fn min_dcache_line_size() -> usize {
+    const CTR_EL0_DMINLINE_SHIFT: usize = 16;
+    const CTR_EL0_DMINLINE_MASK: usize = 0xf;
+
+    let ctr_el0 = read_sysreg!("ctr_el0");
+    let dminline = (ctr_el0 >> CTR_EL0_DMINLINE_SHIFT) & CTR_EL0_DMINLINE_MASK;
+    4 << dminline
+}
+
+/// Applies `op` to every D-cache line covering `range`, aligning the start address down to the
+/// minimum cacheline granule, then issues a `DSB SY` so the maintenance is complete before
+/// returning.
+fn dcache_range_op(range: &Range<usize>, op: unsafe fn(usize)) {
+    let line_size = min_dcache_line_size();
+    let start = range.start & !(line_size - 1);
+
+    let mut line = start;
+    while line < range.end {
+        // SAFETY: `op` only performs cache maintenance on the given address; it doesn't access
+        // memory in a way that could violate Rust's aliasing rules.
+        unsafe { op(line) };
+        line += line_size;
+    }
+
+    // SAFETY: DSB SY is always safe to execute; it just waits for prior memory accesses (here, the
+    // cache maintenance operations above) to complete.
+    unsafe { asm!("dsb sy", options(nostack)) };
+}
+
+/// Cleans the D-cache line containing `va` to the point of coherency (DC CVAC).
+///
+/// # Safety
+///
+/// `va` must be a valid virtual address; this doesn't dereference it as data, but some
+/// implementations require it to be mapped.
+unsafe fn dc_cvac(va: usize) {
+    // SAFETY: the caller guarantees `va` is valid for cache maintenance.
+    unsafe { asm!("dc cvac, {0}", in(reg) va, options(nostack)) };
+}
+
+/// Cleans and invalidates the D-cache line containing `va` to the point of coherency (DC CIVAC).
+///
+/// # Safety
+///
+/// `va` must be a valid virtual address; this doesn't dereference it as data, but some
+/// implementations require it to be mapped.
+unsafe fn dc_civac(va: usize) {
+    // SAFETY: the caller guarantees `va` is valid for cache maintenance.
+    unsafe { asm!("dc civac, {0}", in(reg) va, options(nostack)) };
 }
\ No newline at end of file